@@ -1,32 +1,243 @@
 #[allow(warnings)]
 mod bindings;
-use serde_json::Value as JsonValue;
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 
 use bindings::{
     exports::supabase::wrappers::routines::Guest,
     supabase::wrappers::{
         http, time,
-        types::{Cell, Context, FdwError, FdwResult, OptionsType, Row, TypeOid},
+        types::{
+            Cell, Context, FdwError, FdwResult, Options, OptionsType, Qual, Row, Sort, TypeOid,
+            Value,
+        },
         utils,
     },
 };
 
+// Square API request used to scan customers
+#[derive(Debug, Default, PartialEq)]
+enum ScanRequest {
+    // ListCustomers, returns all customers
+    #[default]
+    List,
+    // SearchCustomers with the query object built from pushed down quals
+    Search(JsonValue),
+    // RetrieveCustomer for a single customer id
+    Retrieve(String),
+    // BulkRetrieveCustomers for a non-empty list of customer ids
+    BulkRetrieve(Vec<String>),
+    // No request, the quals match no customer
+    Empty,
+}
+
+// Square sort field and order pushed down from ORDER BY
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SortSpec {
+    field: &'static str,
+    order: &'static str,
+}
+
+// What to do when a JSON value cannot be converted to the column type
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum TypeMismatch {
+    // Silently set the cell to NULL
+    Null,
+    // Set the cell to NULL and report a warning once per column
+    #[default]
+    Warn,
+    // Fail the scan
+    Error,
+}
+
+impl TypeMismatch {
+    fn from_option(value: Option<String>) -> Result<Self, FdwError> {
+        match value.as_deref() {
+            None => Ok(Self::default()),
+            Some("null") => Ok(Self::Null),
+            Some("warn") => Ok(Self::Warn),
+            Some("error") => Ok(Self::Error),
+            Some(v) => Err(format!(
+                "invalid type_mismatch option '{}', expect 'null', 'warn' or 'error'",
+                v
+            )),
+        }
+    }
+}
+
+// Mapping from column names to JSON pointers of customer fields, set by the 'column_map'
+// table option. Columns not in the option map to the field of the same name, or to a
+// nested field if the column name is dotted, e.g. 'address.locality'. The empty pointer
+// maps a column to the whole customer object.
+#[derive(Debug, Default, Clone)]
+struct ColumnMap(HashMap<String, String>);
+
+impl ColumnMap {
+    fn from_option(value: Option<String>) -> Result<Self, FdwError> {
+        let Some(value) = value else {
+            return Ok(Self::default());
+        };
+        let map: HashMap<String, String> = serde_json::from_str(&value)
+            .map_err(|e| format!("invalid column_map option: {}", e))?;
+        if let Some((col, ptr)) = map
+            .iter()
+            .find(|(_, ptr)| !ptr.is_empty() && !ptr.starts_with('/'))
+        {
+            return Err(format!(
+                "invalid column_map option: '{}' of column '{}' is not a JSON pointer",
+                ptr, col
+            ));
+        }
+        Ok(Self(map))
+    }
+
+    // Map a column to the whole customer object, unless it is mapped by the option
+    fn set_whole_record(&mut self, col_name: String) {
+        self.0.entry(col_name).or_default();
+    }
+
+    // JSON pointer of the customer field a column maps to
+    fn pointer(&self, col_name: &str) -> String {
+        match self.0.get(col_name) {
+            Some(ptr) => ptr.clone(),
+            None => col_name
+                .split('.')
+                .map(|seg| format!("/{}", seg.replace('~', "~0").replace('/', "~1")))
+                .collect(),
+        }
+    }
+
+    // Top level customer field a column maps to, None if it maps to a nested field
+    fn field(&self, col_name: &str) -> Option<String> {
+        let ptr = self.pointer(col_name);
+        let seg = ptr.strip_prefix('/')?;
+        if seg.contains('/') {
+            return None;
+        }
+        Some(seg.replace("~1", "/").replace("~0", "~"))
+    }
+
+    // Find the value of a column in a customer object
+    fn lookup<'a>(&self, src_row: &'a JsonValue, col_name: &str) -> Option<&'a JsonValue> {
+        match self.0.get(col_name) {
+            Some(ptr) => src_row.pointer(ptr),
+            None => src_row
+                .get(col_name)
+                .or_else(|| src_row.pointer(&self.pointer(col_name))),
+        }
+    }
+}
+
+// State of a foreign table scan, reset at each begin_scan
 #[derive(Debug, Default)]
-struct ExampleFdw {
-    base_url: String,
+struct ScanState {
+    req: ScanRequest,
+    sort: Option<SortSpec>,
+    row_limit: Option<usize>, // Number of rows to produce, None if no limit pushed down
+    row_cnt: usize,
     src_rows: Vec<JsonValue>,
     src_idx: usize,
-    access_token: String, // Add an access token field for Square API
+    cursor: Option<String>, // Cursor of the next page, None if no more pages
+    retrieve_idx: usize,    // Index of the next customer id to bulk retrieve
+    page_cnt: usize,        // Number of pages fetched in the current scan
+    column_map: ColumnMap,
+    type_mismatch: TypeMismatch,
+    mismatch_cols: HashSet<String>, // Columns already warned about type mismatch
+}
+
+// What to do on insert when a customer with the same conflict key already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnConflict {
+    // Fail the insert
+    Error,
+    // Skip the row
+    Skip,
+    // Update the existing customer with the row
+    Update,
+}
+
+// State of a foreign table modify, reset at each begin_modify
+#[derive(Debug, Default)]
+struct ModifyState {
+    column_map: ColumnMap,
+    rowid_column: String,
+    ignore_not_found: bool, // Deleting a non-existing customer is a no-op
+    on_conflict: Option<(OnConflict, String)>, // Upsert action and the conflict key field
+    insert_cnt: u64,        // Number of rows inserted
+    idempotency_ns: String, // Namespace mixed into idempotency keys
+    dry_run: bool,          // Report write requests instead of sending them
+    invalid_rows: Vec<String>, // Rows failed local validation, nothing is sent once not empty
+    row_occurs: HashMap<u64, u64>, // Number of times identical rows were inserted, by row hash
+    creates: Vec<(u64, String, JsonValue)>, // Row number, idempotency key and customer to create
+    updates: Vec<(String, JsonValue)>, // Customer id and fields to update
+    deletes: Vec<String>,   // Customer ids to delete
+}
+
+// Square OAuth credentials used to refresh an expired access token
+#[derive(Debug)]
+struct OAuthConfig {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
 }
 
-// Pointer for the static FDW instance
+#[derive(Debug, Default)]
+struct ExampleFdw {
+    base_url: String, // Root URL of Square API, endpoint paths are joined onto it
+    access_token: RefCell<String>, // Replaced when it is refreshed by OAuth
+    oauth: Option<OAuthConfig>,
+    square_version: String,
+    max_retries: u32,   // Max number of retries of a failed idempotent request
+    retry_base_ms: u64, // Base delay of exponential backoff between retries
+    scan: ScanState,
+    modify: ModifyState,
+}
+
+// Customer fields set by Square, they are ignored when creating customers
+const READ_ONLY_FIELDS: &[&str] = &[
+    "id",
+    "created_at",
+    "updated_at",
+    "version",
+    "creation_source",
+    "group_ids",
+    "segment_ids",
+];
+
+// Square API root URLs of each environment
+const PRODUCTION_URL: &str = "https://connect.squareup.com";
+const SANDBOX_URL: &str = "https://connect.squareupsandbox.com";
+
+// Square API version used if not set by the 'square_version' server option
+const DEFAULT_SQUARE_VERSION: &str = "2024-10-17";
+
+// Upper bound of the delay between retries
+const MAX_RETRY_DELAY_MS: u64 = 30_000;
+
+// Max page size of ListCustomers and SearchCustomers
+const MAX_PAGE_SIZE: usize = 100;
+
+// Max number of customer ids in one BulkRetrieveCustomers request
+const BULK_RETRIEVE_SIZE: usize = 100;
+
+// Max number of customers in one bulk create, update or delete request
+const BULK_WRITE_SIZE: usize = 100;
+
+// Pointer for the static FDW instance.
+//
+// The host creates a separate Wasm instance for each foreign scan or modify node, so this
+// instance only serves one scan or modify at a time, its state is reset when they begin.
 static mut INSTANCE: *mut ExampleFdw = std::ptr::null_mut::<ExampleFdw>();
 
 impl ExampleFdw {
-    // Initialize FDW instance
+    // Initialize FDW instance, replacing the previous one if init is called again
     fn init_instance() {
         let instance = Self::default();
         unsafe {
+            if !INSTANCE.is_null() {
+                drop(Box::from_raw(INSTANCE));
+            }
             INSTANCE = Box::leak(Box::new(instance));
         }
     }
@@ -34,6 +245,1320 @@ impl ExampleFdw {
     fn this_mut() -> &'static mut Self {
         unsafe { &mut (*INSTANCE) }
     }
+
+    // Prepare the headers required for Square API (authorization)
+    fn headers(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "authorization".to_owned(),
+                format!("Bearer {}", self.access_token.borrow()),
+            ),
+            ("content-type".to_owned(), "application/json".to_owned()),
+            ("user-agent".to_owned(), "SquareCustomers FDW".to_owned()),
+            ("square-version".to_owned(), self.square_version.clone()),
+        ]
+    }
+
+    // Make a request to Square API and return the response whatever its status is.
+    //
+    // Idempotent requests are retried with exponential backoff when Square is rate limiting
+    // or failing temporarily, honoring the 'Retry-After' header if present.
+    fn send(
+        &self,
+        method: http::Method,
+        url: String,
+        body: Option<&JsonValue>,
+    ) -> Result<http::Response, FdwError> {
+        // Obtain the access token by OAuth before the first request if it is not set, so no
+        // token is requested by instances that never call Square
+        let mut refreshed = false;
+        if self.access_token.borrow().is_empty() && self.oauth.is_some() {
+            self.refresh_access_token()?;
+            refreshed = true;
+        }
+
+        let mut req = http::Request {
+            method,
+            url,
+            headers: self.headers(),
+            body: body.map(|v| v.to_string()).unwrap_or_default(),
+        };
+        let max_attempts = if is_idempotent(method, &req.url) {
+            self.max_retries + 1
+        } else {
+            1
+        };
+
+        let mut attempt = 1;
+        loop {
+            let resp = match method {
+                http::Method::Get => http::get(&req),
+                http::Method::Post => http::post(&req),
+                http::Method::Put => http::put(&req),
+                http::Method::Patch => http::patch(&req),
+                http::Method::Delete => http::delete(&req),
+            };
+
+            // Refresh the expired access token once and retry with the new token, it does not
+            // count as an attempt
+            if let Ok(resp) = &resp {
+                if resp.status_code == 401 && self.oauth.is_some() && !refreshed {
+                    let expired = parse_json(&resp.body)
+                        .ok()
+                        .and_then(|v| v.get("errors").cloned())
+                        .is_some_and(|v| has_error_code(&v, "ACCESS_TOKEN_EXPIRED"));
+                    if expired {
+                        self.refresh_access_token()?;
+                        refreshed = true;
+                        req.headers = self.headers();
+                        continue;
+                    }
+                }
+            }
+
+            // Return the response unless it can be retried
+            let retry_after = match &resp {
+                Ok(resp) if resp.status_code == 429 || resp.status_code >= 500 => {
+                    retry_after_ms(resp)
+                }
+                Ok(_) => return resp,
+                Err(_) => None,
+            };
+
+            if attempt >= max_attempts {
+                return match resp {
+                    Ok(resp) if max_attempts == 1 => Ok(resp),
+                    Ok(resp) => Err(format!(
+                        "{}, gave up after {} attempts",
+                        self.check_response(method, &resp).err().unwrap_or_default(),
+                        attempt
+                    )),
+                    Err(e) => Err(format!(
+                        "{} {} failed after {} attempts: {}",
+                        method_name(method),
+                        req.url,
+                        attempt,
+                        e
+                    )),
+                };
+            }
+
+            // Back off exponentially with jitter, the delay is between half and full of
+            // retry_base_ms * 2^(attempt - 1)
+            let delay = retry_after.unwrap_or_else(|| {
+                let backoff = self
+                    .retry_base_ms
+                    .saturating_mul(1 << (attempt - 1).min(20))
+                    .min(MAX_RETRY_DELAY_MS);
+                // Mix in the response, which differs between concurrent sessions failing on
+                // the same URL at the same second
+                let resp_id = match &resp {
+                    Ok(resp) => resp
+                        .headers
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case("x-request-id"))
+                        .map(|(_, v)| v.clone())
+                        .unwrap_or_else(|| resp.body.clone()),
+                    Err(e) => e.clone(),
+                };
+                let seed = format!("{}:{}:{}:{}", time::epoch_secs(), attempt, req.url, resp_id);
+                backoff / 2 + fnv1a64(seed.as_bytes()) % (backoff / 2 + 1)
+            });
+            time::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    // Get a new access token with the OAuth refresh token. It is only kept in this instance,
+    // so it is used for the rest of the current scan or modify, not by later queries: the FDW
+    // cannot write server options back and keeps no state shared between instances.
+    fn refresh_access_token(&self) -> FdwResult {
+        let oauth = self
+            .oauth
+            .as_ref()
+            .ok_or("cannot refresh access token, OAuth client is not set in server options")?;
+        let body = json!({
+            "client_id": oauth.client_id,
+            "client_secret": oauth.client_secret,
+            "grant_type": "refresh_token",
+            "refresh_token": oauth.refresh_token,
+        });
+
+        // ObtainToken is authorized by the client secret, not by the expired access token
+        let req = http::Request {
+            method: http::Method::Post,
+            url: format!("{}/oauth2/token", self.base_url),
+            headers: self
+                .headers()
+                .into_iter()
+                .filter(|(k, _)| k != "authorization")
+                .collect(),
+            body: body.to_string(),
+        };
+        let resp = http::post(&req).map_err(|e| format!("POST {} failed: {}", req.url, e))?;
+        self.check_response(http::Method::Post, &resp)
+            .map_err(|e| format!("cannot refresh access token, {}", e))?;
+
+        let resp_json = parse_json(&resp.body)?;
+        let access_token = resp_json
+            .get("access_token")
+            .and_then(|v| v.as_str())
+            .ok_or("cannot find 'access_token' field in the response")?;
+        *self.access_token.borrow_mut() = access_token.to_owned();
+
+        Ok(())
+    }
+
+    // URL of the Customers API endpoint
+    fn customers_url(&self) -> String {
+        format!("{}/v2/customers", self.base_url)
+    }
+
+    // Make a request to Square API, non-success responses are turned into errors
+    fn request(
+        &self,
+        method: http::Method,
+        url: String,
+        body: Option<&JsonValue>,
+    ) -> Result<http::Response, FdwError> {
+        let resp = self.send(method, url, body)?;
+        self.check_response(method, &resp)?;
+        Ok(resp)
+    }
+
+    // Turn a non-success response into an error describing Square errors in its body
+    fn check_response(&self, method: http::Method, resp: &http::Response) -> FdwResult {
+        if (200..300).contains(&resp.status_code) {
+            return Ok(());
+        }
+
+        // Square returns errors like {"errors": [{"category": "...", "code": "...", ...}]}
+        let errors = parse_json(&resp.body)
+            .ok()
+            .and_then(|v| v.get("errors").cloned())
+            .filter(|v| v.as_array().is_some_and(|v| !v.is_empty()));
+        let detail = match &errors {
+            Some(errors) => format_errors(errors),
+            None => resp.body.chars().take(200).collect(),
+        };
+
+        // Point auth problems at the access token or OAuth scopes
+        let errors = errors.unwrap_or_default();
+        let hint = if has_error_code(&errors, "INSUFFICIENT_SCOPES") {
+            ", check the access token is granted the CUSTOMERS_READ and CUSTOMERS_WRITE OAuth scopes"
+        } else if resp.status_code == 401 || has_error(&errors, "category", "AUTHENTICATION_ERROR")
+        {
+            ", check the access token in server options is valid and not expired"
+        } else {
+            ""
+        };
+
+        let header = |name: &str| {
+            resp.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        let square_version = header("square-version").unwrap_or(&self.square_version);
+        let request_id = match header("x-request-id") {
+            Some(id) => format!(", request id: {}", id),
+            None => String::default(),
+        };
+
+        Err(format!(
+            "Square API {} {} returned status {}: {}{} (Square-Version: {}{})",
+            method_name(method),
+            resp.url,
+            resp.status_code,
+            detail,
+            hint,
+            square_version,
+            request_id
+        ))
+    }
+
+    // Apply on_conflict to the buffered customers to create. Customers with a conflict key
+    // value that exists, in this statement or in Square, are skipped or turned into updates.
+    fn resolve_conflicts(&mut self) -> FdwResult {
+        let Some((action, key)) = self.modify.on_conflict.clone() else {
+            return Ok(());
+        };
+        if !self.modify.invalid_rows.is_empty() {
+            return Ok(());
+        }
+
+        // Conflict key values to be created by this statement. SearchCustomers is eventually
+        // consistent, so rows sharing a value are matched locally instead.
+        let mut created_keys: HashSet<String> = HashSet::new();
+        for (row_no, idempotency_key, body) in std::mem::take(&mut self.modify.creates) {
+            if let Some(value) = body.get(&key).and_then(|v| v.as_str()).map(str::to_owned) {
+                if created_keys.contains(&value) {
+                    self.resolve_duplicate(action, &key, &value, body)?;
+                    continue;
+                }
+                if let Some(id) = self.find_conflict(action, &key, &value)? {
+                    if action == OnConflict::Update {
+                        self.modify.updates.push((id, body));
+                    }
+                    continue;
+                }
+                created_keys.insert(value);
+            }
+            self.modify.creates.push((row_no, idempotency_key, body));
+        }
+
+        Ok(())
+    }
+
+    // Find the existing customer whose conflict key field equals the value, returns its id
+    fn find_conflict(
+        &self,
+        action: OnConflict,
+        key: &str,
+        value: &str,
+    ) -> Result<Option<String>, FdwError> {
+        let body = json!({
+            "query": { "filter": { key: { "exact": value } } },
+            "limit": 2,
+        });
+        let url = format!("{}/search", self.customers_url());
+        let resp = self.request(http::Method::Post, url, Some(&body))?;
+        let resp_json = parse_json(&resp.body)?;
+        let ids: Vec<&str> = resp_json
+            .get("customers")
+            .and_then(|v| v.as_array())
+            .map(|v| {
+                v.iter()
+                    .filter_map(|c| c.get("id").and_then(|v| v.as_str()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        match (action, ids.as_slice()) {
+            (_, []) => Ok(None),
+            (OnConflict::Error, [id, ..]) => Err(format!(
+                "customer with {} '{}' already exists in Square: '{}'",
+                key, value, id
+            )),
+            (OnConflict::Update, [_, _, ..]) => Err(format!(
+                "cannot update customer with {} '{}', more than one customer has it in Square",
+                key, value
+            )),
+            (_, [id, ..]) => Ok(Some(id.to_string())),
+        }
+    }
+
+    // Apply the conflict action to a row whose conflict key value is already to be created by
+    // this statement
+    fn resolve_duplicate(
+        &mut self,
+        action: OnConflict,
+        key: &str,
+        value: &str,
+        body: JsonValue,
+    ) -> FdwResult {
+        match action {
+            OnConflict::Error => Err(format!(
+                "customer with {} '{}' is inserted more than once in the statement",
+                key, value
+            )),
+            OnConflict::Skip => Ok(()),
+            OnConflict::Update => {
+                // Merge the row into the buffered customer to create
+                let create = self
+                    .modify
+                    .creates
+                    .iter_mut()
+                    .find(|(_, _, c)| c.get(key).and_then(|v| v.as_str()) == Some(value));
+                if let Some((_, _, create)) = create {
+                    if let (Some(create), Some(fields)) = (create.as_object_mut(), body.as_object())
+                    {
+                        create.extend(fields.clone());
+                    }
+                    return Ok(());
+                }
+                Err(format!(
+                    "cannot update customer with {} '{}', it is not found in the statement",
+                    key, value
+                ))
+            }
+        }
+    }
+
+    // Send a bulk request and get the per-row responses, keyed by idempotency key or customer id
+    fn post_bulk(
+        &self,
+        endpoint: &str,
+        body: &JsonValue,
+    ) -> Result<JsonMap<String, JsonValue>, FdwError> {
+        let url = format!("{}/{}", self.customers_url(), endpoint);
+
+        // In dry run mode, report the request and treat it as succeeded for all rows
+        if self.modify.dry_run {
+            let body = serde_json::to_string_pretty(body).map_err(|e| e.to_string())?;
+            utils::report_notice(&format!("[dry run] POST {}\n{}", url, body));
+            return Ok(JsonMap::new());
+        }
+
+        let resp = self.request(http::Method::Post, url, Some(body))?;
+        let mut resp_json = parse_json(&resp.body)?;
+        match resp_json.get_mut("responses").map(JsonValue::take) {
+            Some(JsonValue::Object(responses)) => Ok(responses),
+            _ => Err("cannot find 'responses' field in the response".to_owned()),
+        }
+    }
+
+    // Create the buffered customers with BulkCreateCustomers, in batches of up to 100
+    fn flush_creates(&mut self) -> FdwResult {
+        let creates = std::mem::take(&mut self.modify.creates);
+        if !self.modify.invalid_rows.is_empty() {
+            return Ok(());
+        }
+        for batch in creates.chunks(BULK_WRITE_SIZE) {
+            self.create_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    // Create a batch of customers with one BulkCreateCustomers request
+    fn create_batch(&self, creates: &[(u64, String, JsonValue)]) -> FdwResult {
+        let customers: JsonMap<String, JsonValue> = creates
+            .iter()
+            .map(|(_, key, body)| (key.clone(), body.clone()))
+            .collect();
+        let responses = self.post_bulk("bulk-create", &json!({ "customers": customers }))?;
+
+        // Report rows failed to create by their row number in the statement
+        let errors: Vec<String> = creates
+            .iter()
+            .filter_map(|(row_no, key, _)| {
+                let errors = responses.get(key)?.get("errors")?;
+                Some(format!("row {}: {}", row_no, format_errors(errors)))
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(format!(
+                "failed to create {} of {} customers, {}",
+                errors.len(),
+                creates.len(),
+                errors.join("; ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Update the buffered customers with BulkUpdateCustomers, in batches of up to 100. A
+    // customer can only be updated once in a batch, so a repeated customer starts a new batch.
+    fn flush_updates(&mut self) -> FdwResult {
+        let updates = std::mem::take(&mut self.modify.updates);
+        if !self.modify.invalid_rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut batches: Vec<Vec<(String, JsonValue)>> = Vec::new();
+        for (id, body) in updates {
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.len() < BULK_WRITE_SIZE && !batch.iter().any(|(v, _)| *v == id) =>
+                {
+                    batch.push((id, body))
+                }
+                _ => batches.push(vec![(id, body)]),
+            }
+        }
+        for batch in &batches {
+            self.update_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    // Update a batch of customers with one BulkUpdateCustomers request
+    fn update_batch(&self, updates: &[(String, JsonValue)]) -> FdwResult {
+        let customers: JsonMap<String, JsonValue> = updates.iter().cloned().collect();
+        let responses = self.post_bulk("bulk-update", &json!({ "customers": customers }))?;
+
+        // Report customers failed to update by their id
+        let errors: Vec<String> = updates
+            .iter()
+            .filter_map(|(id, body)| {
+                let errors = responses.get(id)?.get("errors")?;
+                if has_error_code(errors, "CONFLICT") {
+                    let version = body.get("version").and_then(|v| v.as_i64());
+                    return Some(self.conflict_error(id, version));
+                }
+                Some(format!("customer '{}': {}", id, format_errors(errors)))
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(format!(
+                "failed to update {} of {} customers, {}",
+                errors.len(),
+                updates.len(),
+                errors.join("; ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Delete the buffered customers with BulkDeleteCustomers
+    fn flush_deletes(&mut self) -> FdwResult {
+        let deletes = std::mem::take(&mut self.modify.deletes);
+        if deletes.is_empty() || !self.modify.invalid_rows.is_empty() {
+            return Ok(());
+        }
+
+        let responses = self.post_bulk("bulk-delete", &json!({ "customer_ids": deletes }))?;
+
+        // Report customers failed to delete by their id, non-existing customers can be ignored
+        let errors: Vec<String> = deletes
+            .iter()
+            .filter_map(|id| {
+                let errors = responses.get(id)?.get("errors")?;
+                if self.modify.ignore_not_found && has_error_code(errors, "NOT_FOUND") {
+                    return None;
+                }
+                Some(format!("customer '{}': {}", id, format_errors(errors)))
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(format!(
+                "failed to delete {} of {} customers, {}",
+                errors.len(),
+                deletes.len(),
+                errors.join("; ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Describe a version conflict of a customer, with its current version in Square if it
+    // can be retrieved
+    fn conflict_error(&self, id: &str, version: Option<i64>) -> FdwError {
+        let url = format!("{}/{}", self.customers_url(), percent_encode(id));
+        let current = self
+            .request(http::Method::Get, url, None)
+            .ok()
+            .and_then(|resp| parse_json(&resp.body).ok())
+            .and_then(|v| v.pointer("/customer/version").and_then(|v| v.as_i64()));
+
+        let fmt = |v: Option<i64>| v.map_or("unknown".to_owned(), |v| v.to_string());
+        format!(
+            "customer '{}' was modified concurrently, expected version {} but current version is {}",
+            id,
+            fmt(version),
+            fmt(current)
+        )
+    }
+
+    // Build a new scan state with the Square API request, sort and row limit to push down
+    fn plan_scan(ctx: &Context) -> Result<ScanState, FdwError> {
+        let opts = ctx.get_options(OptionsType::Table);
+        let mut column_map = ColumnMap::from_option(opts.get("column_map"))?;
+        column_map.set_whole_record(opts.require_or("attrs_column", "attrs"));
+        let type_mismatch = TypeMismatch::from_option(opts.get("type_mismatch"))?;
+
+        // Look up customers by id directly if possible, then push down supported quals to
+        // SearchCustomers, otherwise list all customers
+        let quals = ctx.get_quals();
+        let scan_req = match build_id_request(&quals, &column_map) {
+            Some(req) => req,
+            None => match build_search_filter(&quals, &column_map) {
+                Some(filter) => ScanRequest::Search(json!({ "filter": filter })),
+                None => ScanRequest::List,
+            },
+        };
+
+        // Push down ORDER BY, Square uses its default order if not specified
+        let sorts = ctx.get_sorts();
+        let sort = build_sort(&sorts, &column_map);
+
+        // Push down LIMIT and OFFSET only if Square returns exactly the rows Postgres needs,
+        // that is no quals to be checked locally and the whole ORDER BY is pushed down
+        let sorted = sorts.is_empty() || (sorts.len() == 1 && sort.is_some());
+        // A limit too large for usize is not pushed down
+        let row_limit = match ctx.get_limit() {
+            Some(limit) if quals.is_empty() && sorted => {
+                usize::try_from(limit.count().saturating_add(limit.offset()).max(0)).ok()
+            }
+            _ => None,
+        };
+
+        Ok(ScanState {
+            req: scan_req,
+            sort,
+            row_limit,
+            column_map,
+            type_mismatch,
+            ..Default::default()
+        })
+    }
+
+    // Start scanning from the first page of Square Customers API
+    fn start_scan(&mut self) -> FdwResult {
+        self.scan.row_cnt = 0;
+        self.scan.cursor = None;
+        self.scan.retrieve_idx = 0;
+        self.scan.page_cnt = 0;
+
+        // LIMIT 0 needs no rows, Square cannot be asked for an empty page
+        if self.scan.row_limit == Some(0) {
+            self.scan.src_rows = Vec::new();
+            self.scan.src_idx = 0;
+            return Ok(());
+        }
+
+        self.fetch_page()
+    }
+
+    // Fetch the next page of customers from Square API and replace the buffered rows
+    fn fetch_page(&mut self) -> FdwResult {
+        // Make a request to Square API, passing the cursor returned by the previous page if any
+        self.scan.src_idx = 0;
+        self.scan.page_cnt += 1;
+        match &self.scan.req {
+            ScanRequest::List => {
+                let mut params = Vec::new();
+                if let Some(limit) = self.page_size() {
+                    params.push(format!("limit={}", limit));
+                }
+                if let Some(sort) = &self.scan.sort {
+                    params.push(format!("sort_field={}", sort.field));
+                    params.push(format!("sort_order={}", sort.order));
+                }
+                if let Some(cursor) = &self.scan.cursor {
+                    params.push(format!("cursor={}", percent_encode(cursor)));
+                }
+                let url = if params.is_empty() {
+                    self.customers_url()
+                } else {
+                    format!("{}?{}", self.customers_url(), params.join("&"))
+                };
+                let resp = self.request(http::Method::Get, url, None)?;
+                self.set_customers_page(&parse_json(&resp.body)?)?;
+            }
+            ScanRequest::Search(query) => {
+                let mut body = json!({ "query": query });
+                if let Some(limit) = self.page_size() {
+                    body["limit"] = json!(limit);
+                }
+                if let Some(sort) = &self.scan.sort {
+                    body["query"]["sort"] = json!({ "field": sort.field, "order": sort.order });
+                }
+                if let Some(cursor) = &self.scan.cursor {
+                    body["cursor"] = json!(cursor);
+                }
+                let url = format!("{}/search", self.customers_url());
+                let resp = self.request(http::Method::Post, url, Some(&body))?;
+                self.set_customers_page(&parse_json(&resp.body)?)?;
+            }
+            ScanRequest::Retrieve(id) => {
+                let url = format!("{}/{}", self.customers_url(), percent_encode(id));
+                let resp = self.send(http::Method::Get, url, None)?;
+
+                // A non-existing customer id simply matches no rows
+                self.scan.src_rows = if resp.status_code == 404 {
+                    Vec::new()
+                } else {
+                    self.check_response(http::Method::Get, &resp)?;
+                    let resp_json = parse_json(&resp.body)?;
+                    let customer = resp_json
+                        .get("customer")
+                        .ok_or("cannot find 'customer' field in the response")?;
+                    vec![customer.to_owned()]
+                };
+            }
+            ScanRequest::Empty => {
+                self.scan.src_rows = Vec::new();
+            }
+            ScanRequest::BulkRetrieve(ids) => {
+                // Square accepts at most 100 customer ids in one request
+                let end = ids.len().min(self.scan.retrieve_idx + BULK_RETRIEVE_SIZE);
+                let chunk = &ids[self.scan.retrieve_idx..end];
+                let url = format!("{}/bulk-retrieve", self.customers_url());
+                let body = json!({ "customer_ids": chunk });
+                let resp = self.request(http::Method::Post, url, Some(&body))?;
+                let resp_json = parse_json(&resp.body)?;
+                let responses = resp_json
+                    .get("responses")
+                    .and_then(|v| v.as_object())
+                    .ok_or("cannot find 'responses' field in the response")?;
+
+                // Keep the order of the requested ids, ids not found have no 'customer' field
+                self.scan.src_rows = chunk
+                    .iter()
+                    .filter_map(|id| responses.get(id).and_then(|r| r.get("customer")))
+                    .cloned()
+                    .collect();
+                self.scan.retrieve_idx = end;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Replace the buffered rows with customers in a ListCustomers or SearchCustomers response
+    fn set_customers_page(&mut self, resp_json: &JsonValue) -> FdwResult {
+        // Square omits the field when there are no customers
+        self.scan.src_rows = match resp_json.get("customers") {
+            Some(customers) => customers
+                .as_array()
+                .ok_or("customers field is not an array")?
+                .to_owned(),
+            None => Vec::new(),
+        };
+
+        // Remember the cursor for the next page, it is absent on the last page
+        self.scan.cursor = resp_json
+            .get("cursor")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_owned());
+
+        Ok(())
+    }
+
+    // Page size to request, only rows needed by the pushed down limit are fetched
+    fn page_size(&self) -> Option<usize> {
+        self.scan.row_limit.map(|n| n.clamp(1, MAX_PAGE_SIZE))
+    }
+
+    // Check if there are more pages to fetch for the current scan
+    fn has_next_page(&self) -> bool {
+        match &self.scan.req {
+            ScanRequest::List | ScanRequest::Search(_) => self.scan.cursor.is_some(),
+            ScanRequest::Retrieve(_) | ScanRequest::Empty => false,
+            ScanRequest::BulkRetrieve(ids) => self.scan.retrieve_idx < ids.len(),
+        }
+    }
+}
+
+// Build a customer object from the columns of a row, columns mapped to nested fields become
+// nested objects, e.g. 'address.locality' becomes {"address": {"locality": ...}}.
+//
+// NULL cells are skipped, or set to JSON null if keep_nulls is true.
+fn build_customer(
+    row: &Row,
+    column_map: &ColumnMap,
+    skip_fields: &[&str],
+    keep_nulls: bool,
+) -> Result<JsonValue, FdwError> {
+    let mut customer = json!({});
+
+    for (col_name, cell) in row.cols().iter().zip(row.cells().iter()) {
+        let ptr = column_map.pointer(col_name);
+
+        // Skip the whole record column and Square managed fields
+        let Some(path) = ptr.strip_prefix('/') else {
+            continue;
+        };
+        let segs: Vec<String> = path
+            .split('/')
+            .map(|seg| seg.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        if skip_fields.contains(&segs[0].as_str()) {
+            continue;
+        }
+
+        let value = match cell {
+            Some(cell) => cell_to_json(cell)?,
+            None if keep_nulls => JsonValue::Null,
+            None => continue,
+        };
+
+        // Create the nested objects along the path
+        let mut target = &mut customer;
+        for seg in &segs[..segs.len() - 1] {
+            if !target.get(seg).is_some_and(|v| v.is_object()) {
+                target[seg] = json!({});
+            }
+            target = &mut target[seg];
+        }
+        target[&segs[segs.len() - 1]] = value;
+    }
+
+    Ok(customer)
+}
+
+// Check if a request can be safely retried, POST requests are only retried for read only
+// endpoints and for BulkCreateCustomers which uses idempotency keys
+fn is_idempotent(method: http::Method, url: &str) -> bool {
+    match method {
+        http::Method::Get | http::Method::Put | http::Method::Delete => true,
+        http::Method::Post => {
+            let path = url.split('?').next().unwrap_or_default();
+            ["/search", "/bulk-retrieve", "/bulk-create"]
+                .iter()
+                .any(|v| path.ends_with(v))
+        }
+        http::Method::Patch => false,
+    }
+}
+
+// Get the delay in milliseconds from 'Retry-After' header in seconds
+fn retry_after_ms(resp: &http::Response) -> Option<u64> {
+    resp.headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("retry-after"))
+        .and_then(|(_, v)| v.trim().parse::<u64>().ok())
+        .map(|secs| secs.saturating_mul(1000).min(MAX_RETRY_DELAY_MS))
+}
+
+// Get a secret server option, either in plain text by its name or from Vault by the secret
+// id in the '<name>_id' option
+fn secret_option(opts: &Options, name: &str) -> Result<String, FdwError> {
+    if let Some(value) = opts.get(name) {
+        return Ok(value);
+    }
+    let secret_id = opts
+        .get(&format!("{}_id", name))
+        .ok_or(format!("either {} or {}_id option is required", name, name))?;
+    utils::get_vault_secret(&secret_id).ok_or(format!(
+        "cannot find {} secret '{}' in Vault",
+        name, secret_id
+    ))
+}
+
+// Name of HTTP method used in error messages
+fn method_name(method: http::Method) -> &'static str {
+    match method {
+        http::Method::Get => "GET",
+        http::Method::Post => "POST",
+        http::Method::Put => "PUT",
+        http::Method::Patch => "PATCH",
+        http::Method::Delete => "DELETE",
+    }
+}
+
+// Format Square errors of a failed request or of a row in a bulk request
+fn format_errors(errors: &JsonValue) -> String {
+    let Some(errors) = errors.as_array() else {
+        return errors.to_string();
+    };
+    errors
+        .iter()
+        .map(|e| {
+            let code = e.get("code").and_then(|v| v.as_str()).unwrap_or("UNKNOWN");
+            let code = match e.get("category").and_then(|v| v.as_str()) {
+                Some(category) => format!("{}/{}", category, code),
+                None => code.to_owned(),
+            };
+            let detail = e.get("detail").and_then(|v| v.as_str()).unwrap_or_default();
+            match e.get("field").and_then(|v| v.as_str()) {
+                Some(field) => format!("{} ({}): {}", code, field, detail),
+                None => format!("{}: {}", code, detail),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Check if Square errors contain the error code
+fn has_error_code(errors: &JsonValue, code: &str) -> bool {
+    has_error(errors, "code", code)
+}
+
+// Check if Square errors contain an error whose key, e.g. 'category', has the value
+fn has_error(errors: &JsonValue, key: &str, value: &str) -> bool {
+    errors.as_array().is_some_and(|errors| {
+        errors
+            .iter()
+            .any(|e| e.get(key).and_then(|v| v.as_str()) == Some(value))
+    })
+}
+
+// Validate a customer in a create or update request against Square constraints, returns
+// the problems found. NULL fields in an update clear the field and are not validated.
+fn validate_customer(customer: &JsonValue, is_create: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    let get_str = |field: &str| customer.get(field).and_then(|v| v.as_str());
+
+    // Field length limits
+    for (field, max_len) in [
+        ("given_name", 300),
+        ("family_name", 300),
+        ("company_name", 500),
+        ("nickname", 100),
+        ("email_address", 254),
+        ("reference_id", 100),
+    ] {
+        if let Some(v) = get_str(field) {
+            if v.chars().count() > max_len {
+                problems.push(format!("{} is longer than {} characters", field, max_len));
+            }
+        }
+    }
+
+    if let Some(v) = get_str("email_address") {
+        if !is_valid_email(v) {
+            problems.push(format!(
+                "email_address '{}' is not a valid email address",
+                v
+            ));
+        }
+    }
+
+    if let Some(v) = get_str("phone_number") {
+        if !is_valid_phone(v) {
+            problems.push(format!(
+                "phone_number '{}' is not in E.164 format, e.g. '+14155552671'",
+                v
+            ));
+        }
+    }
+
+    if let Some(v) = get_str("birthday") {
+        if !is_valid_birthday(v) {
+            problems.push(format!(
+                "birthday '{}' is not in 'YYYY-MM-DD' or 'MM-DD' format",
+                v
+            ));
+        }
+    }
+
+    // Square requires at least one of these fields to create a customer
+    let required = [
+        "given_name",
+        "family_name",
+        "company_name",
+        "email_address",
+        "phone_number",
+    ];
+    if is_create
+        && !required
+            .iter()
+            .any(|f| get_str(f).is_some_and(|v| !v.is_empty()))
+    {
+        problems.push(format!(
+            "at least one of {} is required",
+            required.join(", ")
+        ));
+    }
+
+    problems
+}
+
+// Check email address syntax, like 'local@example.com'
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !local.contains('@')
+        && !email.contains(char::is_whitespace)
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'))
+}
+
+// Check phone number is in E.164 format, like '+14155552671'
+fn is_valid_phone(phone: &str) -> bool {
+    let Some(digits) = phone.strip_prefix('+') else {
+        return false;
+    };
+    (2..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+// Check birthday is like '1998-09-21', or '09-21' and '0000-09-21' without year
+fn is_valid_birthday(birthday: &str) -> bool {
+    let parts: Vec<&str> = birthday.split('-').collect();
+    let (year, month, day) = match parts.as_slice() {
+        [year, month, day] if year.len() == 4 => (*year, *month, *day),
+        [month, day] => ("0000", *month, *day),
+        _ => return false,
+    };
+    if month.len() != 2
+        || day.len() != 2
+        || !parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit()))
+    {
+        return false;
+    }
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse(), month.parse(), day.parse()) else {
+        return false;
+    };
+    (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day)
+}
+
+// Number of days in a month, year 0 stands for an unknown year where February has 29 days
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year == 0 || (year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Get customer id from the rowid cell
+fn rowid_to_id(rowid: &Cell) -> Result<String, FdwError> {
+    match rowid {
+        Cell::String(id) => Ok(id.clone()),
+        _ => Err("rowid column must be a text column of customer id".to_owned()),
+    }
+}
+
+// Convert a cell to JSON value sent to Square API
+fn cell_to_json(cell: &Cell) -> Result<JsonValue, FdwError> {
+    let value = match cell {
+        Cell::Bool(v) => json!(v),
+        Cell::I8(v) => json!(v),
+        Cell::I16(v) => json!(v),
+        Cell::I32(v) => json!(v),
+        Cell::I64(v) => json!(v),
+        Cell::F32(v) => json!(v),
+        Cell::F64(v) | Cell::Numeric(v) => json!(v),
+        Cell::String(v) => json!(v),
+        Cell::Date(v) => json!(format_date(*v)),
+        Cell::Timestamp(v) | Cell::Timestamptz(v) => json!(time::epoch_ms_to_rfc3339(*v)?),
+        Cell::Json(v) => serde_json::from_str(v).map_err(|e| e.to_string())?,
+    };
+    Ok(value)
+}
+
+// Format seconds since Unix epoch as a date like '1998-09-21'
+fn format_date(secs: i64) -> String {
+    // Civil date from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = secs.div_euclid(86_400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// FNV-1a hash, used to derive keys from request content
+fn fnv1a64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Parse response body as JSON
+fn parse_json(body: &str) -> Result<JsonValue, FdwError> {
+    serde_json::from_str(body).map_err(|e| e.to_string())
+}
+
+// Extract customer ids from 'id = ...' or 'id in (...)' quals
+fn build_id_request(quals: &[Qual], column_map: &ColumnMap) -> Option<ScanRequest> {
+    for qual in quals
+        .iter()
+        .filter(|q| column_map.field(&q.field()).as_deref() == Some("id") && q.operator() == "=")
+    {
+        match qual.value() {
+            // An empty id matches no customer, it is not sent as it would hit the list endpoint
+            Value::Cell(Cell::String(id)) if id.is_empty() => return Some(ScanRequest::Empty),
+            Value::Cell(Cell::String(id)) => return Some(ScanRequest::Retrieve(id)),
+            Value::Array(cells) if qual.use_or() => {
+                let mut ids: Vec<String> = Vec::new();
+                for cell in cells {
+                    match cell {
+                        Cell::String(id) if !id.is_empty() && !ids.contains(&id) => ids.push(id),
+                        Cell::String(_) => {}
+                        _ => return None,
+                    }
+                }
+                // Square rejects an empty id list
+                if ids.is_empty() {
+                    return Some(ScanRequest::Empty);
+                }
+                return Some(ScanRequest::BulkRetrieve(ids));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Map ORDER BY to Square sort, only the leading sort key on created_at can be pushed down
+// as Square can only sort by creation time or by its default (name based) order
+fn build_sort(sorts: &[Sort], column_map: &ColumnMap) -> Option<SortSpec> {
+    let sort = sorts.first()?;
+    if column_map.field(&sort.field()).as_deref() != Some("created_at") {
+        return None;
+    }
+    Some(SortSpec {
+        field: "CREATED_AT",
+        order: if sort.reversed() { "DESC" } else { "ASC" },
+    })
+}
+
+// Build SearchCustomers filter from the quals, returns None if no qual can be pushed down.
+//
+// Postgres still applies all the quals locally, so the filter only needs to narrow the
+// result set and can be looser than the quals, e.g. a '>' qual becomes an inclusive range.
+fn build_search_filter(quals: &[Qual], column_map: &ColumnMap) -> Option<JsonValue> {
+    let mut filter = JsonMap::new();
+
+    for qual in quals {
+        // Quals on columns mapped to nested fields cannot be pushed down
+        let Some(field) = column_map.field(&qual.field()) else {
+            continue;
+        };
+        let op = qual.operator();
+        let value = qual.value();
+
+        match field.as_str() {
+            "email_address" | "phone_number" | "reference_id" => {
+                // Square only accepts one text filter per field
+                if filter.contains_key(&field) {
+                    continue;
+                }
+                let Value::Cell(Cell::String(s)) = &value else {
+                    continue;
+                };
+                let text_filter = match op.as_str() {
+                    "=" => json!({ "exact": s }),
+                    "~~" | "~~*" => match fuzzy_term(s) {
+                        Some(term) => json!({ "fuzzy": term }),
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                filter.insert(field, text_filter);
+            }
+            "created_at" | "updated_at" => {
+                let Value::Cell(cell) = &value else {
+                    continue;
+                };
+                let Some(ts) = cell_to_rfc3339(cell) else {
+                    continue;
+                };
+                let bounds: &[&str] = match op.as_str() {
+                    ">" | ">=" => &["start_at"],
+                    "<" | "<=" => &["end_at"],
+                    "=" => &["start_at", "end_at"],
+                    _ => continue,
+                };
+                let range = filter.entry(field).or_insert_with(|| json!({}));
+                for bound in bounds {
+                    range[*bound] = json!(ts);
+                }
+            }
+            "creation_source" => {
+                if filter.contains_key(&field) {
+                    continue;
+                }
+                let values: Vec<String> = match &value {
+                    Value::Cell(Cell::String(s)) => vec![s.clone()],
+                    Value::Array(cells) => cells
+                        .iter()
+                        .filter_map(|c| match c {
+                            Cell::String(s) => Some(s.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => continue,
+                };
+                // 'IN' list is '= ANY' and 'NOT IN' list is '<> ALL'
+                let rule = match (op.as_str(), &value) {
+                    ("=", Value::Cell(_)) => "INCLUDE",
+                    ("<>", Value::Cell(_)) => "EXCLUDE",
+                    ("=", Value::Array(_)) if qual.use_or() => "INCLUDE",
+                    ("<>", Value::Array(_)) if !qual.use_or() => "EXCLUDE",
+                    _ => continue,
+                };
+                if values.is_empty() {
+                    continue;
+                }
+                filter.insert(field, json!({ "values": values, "rule": rule }));
+            }
+            "group_ids" => {
+                if filter.contains_key(&field) {
+                    continue;
+                }
+                // The column is a jsonb array of group ids, map the jsonb operators
+                let group_filter = match (op.as_str(), &value) {
+                    ("@>", Value::Cell(Cell::Json(s))) => {
+                        match serde_json::from_str::<JsonValue>(s) {
+                            Ok(JsonValue::Array(ids)) if !ids.is_empty() => json!({ "all": ids }),
+                            Ok(JsonValue::String(id)) => json!({ "all": [id] }),
+                            _ => continue,
+                        }
+                    }
+                    ("?", Value::Cell(Cell::String(id))) => json!({ "all": [id] }),
+                    ("?|" | "?&", Value::Array(cells)) => {
+                        let ids: Vec<&String> = cells
+                            .iter()
+                            .filter_map(|c| match c {
+                                Cell::String(s) => Some(s),
+                                _ => None,
+                            })
+                            .collect();
+                        if ids.is_empty() {
+                            continue;
+                        }
+                        if op == "?|" {
+                            json!({ "any": ids })
+                        } else {
+                            json!({ "all": ids })
+                        }
+                    }
+                    _ => continue,
+                };
+                filter.insert(field, group_filter);
+            }
+            _ => {}
+        }
+    }
+
+    if filter.is_empty() {
+        None
+    } else {
+        Some(JsonValue::Object(filter))
+    }
+}
+
+// Extract the search term from a LIKE pattern such as '%term%', 'term%' or '%term'
+fn fuzzy_term(pattern: &str) -> Option<&str> {
+    let term = pattern.trim_start_matches('%').trim_end_matches('%');
+    if term.is_empty() || term.contains(['%', '_', '\\']) {
+        return None;
+    }
+    Some(term)
+}
+
+// Convert a timestamp cell to RFC3339 string used by Square time ranges
+fn cell_to_rfc3339(cell: &Cell) -> Option<String> {
+    match cell {
+        Cell::Timestamp(v) | Cell::Timestamptz(v) => time::epoch_ms_to_rfc3339(*v).ok(),
+        Cell::String(s) => time::parse_from_rfc3339(s)
+            .ok()
+            .and_then(|v| time::epoch_ms_to_rfc3339(v).ok()),
+        _ => None,
+    }
+}
+
+// Convert a JSON value in Square API response to a cell of the column type, returns None
+// if the value cannot be coerced to the column type.
+//
+// Besides values of the matching JSON type, below coercions are applied:
+//   - text: numbers and booleans become their JSON text, arrays and objects their JSON string
+//   - integers: numeric strings and numbers without fraction in the column type range
+//   - floats and numeric: numeric strings
+//   - boolean: 'true' and 'false' strings, case insensitive
+fn json_to_cell(src_value: &JsonValue, type_oid: TypeOid) -> Option<Cell> {
+    match type_oid {
+        TypeOid::Bool => json_to_bool(src_value).map(Cell::Bool),
+        TypeOid::I8 => json_to_i64(src_value)
+            .and_then(|v| i8::try_from(v).ok())
+            .map(Cell::I8),
+        TypeOid::I16 => json_to_i64(src_value)
+            .and_then(|v| i16::try_from(v).ok())
+            .map(Cell::I16),
+        TypeOid::I32 => json_to_i64(src_value)
+            .and_then(|v| i32::try_from(v).ok())
+            .map(Cell::I32),
+        TypeOid::I64 => json_to_i64(src_value).map(Cell::I64),
+        TypeOid::F32 => json_to_f64(src_value).map(|v| Cell::F32(v as f32)),
+        TypeOid::F64 => json_to_f64(src_value).map(Cell::F64),
+        TypeOid::Numeric => json_to_f64(src_value).map(Cell::Numeric),
+        TypeOid::String => match src_value {
+            JsonValue::String(v) => Some(Cell::String(v.to_owned())),
+            v => Some(Cell::String(v.to_string())),
+        },
+        TypeOid::Date => src_value.as_str().and_then(parse_date).map(Cell::Date),
+        // Parse timestamp from RFC3339 string format
+        TypeOid::Timestamp => src_value
+            .as_str()
+            .and_then(|v| time::parse_from_rfc3339(v).ok())
+            .map(Cell::Timestamp),
+        TypeOid::Timestamptz => src_value
+            .as_str()
+            .and_then(|v| time::parse_from_rfc3339(v).ok())
+            .map(Cell::Timestamptz),
+        TypeOid::Json => Some(Cell::Json(src_value.to_string())),
+    }
+}
+
+fn json_to_bool(src_value: &JsonValue) -> Option<bool> {
+    match src_value {
+        JsonValue::Bool(v) => Some(*v),
+        JsonValue::String(v) if v.eq_ignore_ascii_case("true") => Some(true),
+        JsonValue::String(v) if v.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
+
+fn json_to_i64(src_value: &JsonValue) -> Option<i64> {
+    match src_value {
+        JsonValue::Number(v) => v.as_i64().or_else(|| {
+            v.as_f64()
+                .filter(|f| f.fract() == 0.0 && *f >= i64::MIN as f64 && *f < i64::MAX as f64)
+                .map(|f| f as i64)
+        }),
+        JsonValue::String(v) => v.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn json_to_f64(src_value: &JsonValue) -> Option<f64> {
+    match src_value {
+        JsonValue::Number(v) => v.as_f64(),
+        JsonValue::String(v) => v.trim().parse().ok().filter(|f: &f64| f.is_finite()),
+        _ => None,
+    }
+}
+
+// Parse a date like '1998-09-21' or a RFC3339 timestamp to seconds since Unix epoch.
+//
+// Square uses '0000' as year in birthday when the year is unknown, which is not a valid
+// Postgres date so it is parsed as None.
+fn parse_date(s: &str) -> Option<i64> {
+    // Plain dates are parsed locally, anything else must be a RFC3339 timestamp
+    let parts: Vec<&str> = s.split('-').collect();
+    if let [year, month, day] = parts.as_slice() {
+        if parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+        {
+            let (year, month, day) = (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+            if year == 0
+                || !(1..=12).contains(&month)
+                || !(1..=days_in_month(year, month)).contains(&day)
+            {
+                return None;
+            }
+            return Some(days_from_civil(year, month, day) * 86_400);
+        }
+    }
+
+    let us = time::parse_from_rfc3339(s).ok()?;
+    let secs = us.div_euclid(1_000_000);
+    Some(secs - secs.rem_euclid(86_400))
+}
+
+// Days since Unix epoch of a civil date, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Percent-encode a query string value or path segment, cursors can contain reserved characters
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
 }
 
 impl Guest for ExampleFdw {
@@ -48,8 +1573,54 @@ impl Guest for ExampleFdw {
 
         // Get API URL and Access Token from foreign server options
         let opts = ctx.get_options(OptionsType::Server);
-        this.base_url = opts.require_or("base_url", "https://connect.squareup.com/v2/customers");
-        this.access_token = opts.require("access_token")?; // Fetch the access token for Square API
+        // An explicit base_url, such as a mock server, takes precedence over the environment.
+        // The full customers endpoint of earlier versions is still accepted.
+        let env_url = match opts.require_or("environment", "production").as_str() {
+            "production" => PRODUCTION_URL,
+            "sandbox" => SANDBOX_URL,
+            other => {
+                return Err(format!(
+                    "invalid environment option '{}', expect 'production' or 'sandbox'",
+                    other
+                ))
+            }
+        };
+        let base_url = opts.require_or("base_url", env_url);
+        this.base_url = base_url
+            .trim_end_matches('/')
+            .trim_end_matches("/v2/customers")
+            .to_owned();
+        // Fetch the access token for Square API, either in plain text or from Vault. It can be
+        // omitted if it is to be obtained by the OAuth refresh token.
+        this.oauth =
+            if opts.get("refresh_token").is_some() || opts.get("refresh_token_id").is_some() {
+                Some(OAuthConfig {
+                    client_id: opts.require("client_id")?,
+                    client_secret: secret_option(&opts, "client_secret")?,
+                    refresh_token: secret_option(&opts, "refresh_token")?,
+                })
+            } else {
+                None
+            };
+        let has_access_token =
+            opts.get("access_token").is_some() || opts.get("access_token_id").is_some();
+        let access_token = if has_access_token || this.oauth.is_none() {
+            Some(secret_option(&opts, "access_token")?)
+        } else {
+            None
+        };
+        this.square_version = opts.require_or("square_version", DEFAULT_SQUARE_VERSION);
+        this.max_retries = opts
+            .require_or("max_retries", "3")
+            .parse()
+            .map_err(|_| "max_retries option must be a non-negative integer")?;
+        this.retry_base_ms = opts
+            .require_or("retry_base_ms", "500")
+            .parse()
+            .map_err(|_| "retry_base_ms option must be a non-negative integer")?;
+
+        // Without an access token, it is obtained by OAuth on the first request
+        this.access_token = RefCell::new(access_token.unwrap_or_default());
 
         Ok(())
     }
@@ -57,42 +1628,15 @@ impl Guest for ExampleFdw {
     fn begin_scan(ctx: &Context) -> FdwResult {
         let this = Self::this_mut();
 
-        // Prepare URL for Square Customers API (no sheet_id, just customers)
-        let opts = ctx.get_options(OptionsType::Table);
-        let url = format!("{}", this.base_url); // Square API endpoint already includes '/customers'
-
-        // Prepare the headers required for Square API (authorization)
-        let headers: Vec<(String, String)> = vec![
-            ("authorization".to_owned(), format!("Bearer {}", this.access_token)),
-            ("content-type".to_owned(), "application/json".to_owned()),
-            ("user-agent".to_owned(), "SquareCustomers FDW".to_owned()),
-        ];
-
-        // Make a request to Square API and parse response as JSON
-        let req = http::Request {
-            method: http::Method::Get,
-            url,
-            headers,
-            body: String::default(),
-        };
-
-        let resp = http::get(&req)?;
-
-        // Parse the JSON response body
-        let resp_json: JsonValue = serde_json::from_str(&resp.body).map_err(|e| e.to_string())?;
+        // Start with a fresh scan state, nothing is carried over from previous scans
+        this.scan = Self::plan_scan(ctx)?;
+        this.start_scan()?;
 
-        // Extract customers from response
-        this.src_rows = resp_json
-            .get("customers")
-            .ok_or("cannot find 'customers' field in the response")?
-            .as_array()
-            .ok_or("customers field is not an array")?
-            .to_owned();
-
-        // Output a Postgres INFO to user (visible in psql), also useful for debugging
+        // Output a Postgres INFO to user (visible in psql), also useful for debugging. It is
+        // not repeated on rescans, which can happen once per outer row of a join.
         utils::report_info(&format!(
             "Retrieved {} customers from Square API.",
-            this.src_rows.len()
+            this.scan.src_rows.len()
         ));
 
         Ok(())
@@ -101,27 +1645,52 @@ impl Guest for ExampleFdw {
     fn iter_scan(ctx: &Context, row: &Row) -> Result<Option<u32>, FdwError> {
         let this = Self::this_mut();
 
-        // If all source rows are consumed, stop data scan
-        if this.src_idx >= this.src_rows.len() {
+        // Stop data scan once enough rows are produced for LIMIT and OFFSET
+        if this.scan.row_limit.is_some_and(|n| this.scan.row_cnt >= n) {
             return Ok(None);
         }
 
+        // If all buffered rows are consumed, fetch the next page if there is one
+        while this.scan.src_idx >= this.scan.src_rows.len() {
+            if !this.has_next_page() {
+                // No more pages, stop data scan
+                return Ok(None);
+            }
+            this.fetch_page()?;
+        }
+
         // Extract current customer row
-        let src_row = &this.src_rows[this.src_idx];
+        let src_row = &this.scan.src_rows[this.scan.src_idx];
 
         // Map Square API fields to target columns
         for tgt_col in ctx.get_columns() {
             let tgt_col_name = tgt_col.name();
-            let src_value = src_row.get(tgt_col_name); // Match JSON field names with column names
-
-            let cell = match tgt_col.type_oid() {
-                TypeOid::String => src_value.and_then(|v| v.as_str()).map(|v| Cell::String(v.to_owned())),
-                TypeOid::I64 => src_value.and_then(|v| v.as_i64()).map(Cell::I64),
-                TypeOid::Timestamp => src_value.and_then(|v| v.as_str()).and_then(|v| {
-                    // Parse timestamp from string format
-                    time::parse_from_rfc3339(v).ok().map(Cell::Timestamp)
-                }),
-                _ => return Err(format!("Column {} data type is not supported", tgt_col_name).into()),
+            let src_value = this.scan.column_map.lookup(src_row, &tgt_col_name);
+
+            let cell = match src_value {
+                Some(v) if !v.is_null() => {
+                    let cell = json_to_cell(v, tgt_col.type_oid());
+                    if cell.is_none() {
+                        // The value cannot be coerced to the column type
+                        let msg = format!(
+                            "cannot convert value {} of customer {} to the type of column '{}'",
+                            v,
+                            src_row.get("id").unwrap_or(&JsonValue::Null),
+                            tgt_col_name
+                        );
+                        match this.scan.type_mismatch {
+                            TypeMismatch::Null => {}
+                            TypeMismatch::Warn => {
+                                if this.scan.mismatch_cols.insert(tgt_col_name.clone()) {
+                                    utils::report_warning(&format!("{}, set to NULL", msg));
+                                }
+                            }
+                            TypeMismatch::Error => return Err(msg),
+                        }
+                    }
+                    cell
+                }
+                _ => None,
             };
 
             // Push the cell to target row
@@ -129,44 +1698,303 @@ impl Guest for ExampleFdw {
         }
 
         // Advance to next source row
-        this.src_idx += 1;
+        this.scan.src_idx += 1;
+        this.scan.row_cnt += 1;
 
         // Tell Postgres we've done one row, and need to scan the next row
         Ok(Some(0))
     }
 
-    fn re_scan(_ctx: &Context) -> FdwResult {
-        Err("re_scan on foreign table is not supported".to_owned())
+    fn re_scan(ctx: &Context) -> FdwResult {
+        let this = Self::this_mut();
+
+        // Only parameterized quals can change between rescans, re-evaluate them and issue a
+        // fresh request if the pushed down request changes
+        if ctx.get_quals().iter().any(|q| q.param().is_some()) {
+            let scan = Self::plan_scan(ctx)?;
+            if scan.req != this.scan.req
+                || scan.sort != this.scan.sort
+                || scan.row_limit != this.scan.row_limit
+            {
+                this.scan = scan;
+                return this.start_scan();
+            }
+        }
+
+        // Replay the buffered rows if the whole result set is in the first page, otherwise
+        // fetch the pages again
+        if this.scan.page_cnt == 1 && !this.has_next_page() {
+            this.scan.src_idx = 0;
+            this.scan.row_cnt = 0;
+            return Ok(());
+        }
+        this.start_scan()
     }
 
     fn end_scan(_ctx: &Context) -> FdwResult {
         let this = Self::this_mut();
-        this.src_rows.clear();
+        this.scan = ScanState::default();
         Ok(())
     }
 
-    fn begin_modify(_ctx: &Context) -> FdwResult {
-        Err("modify on foreign table is not supported".to_owned())
+    fn begin_modify(ctx: &Context) -> FdwResult {
+        let this = Self::this_mut();
+
+        // Start with a fresh modify state, nothing is carried over from previous modifies
+        let opts = ctx.get_options(OptionsType::Table);
+        let mut column_map = ColumnMap::from_option(opts.get("column_map"))?;
+        column_map.set_whole_record(opts.require_or("attrs_column", "attrs"));
+        let ignore_not_found = match opts.require_or("delete_not_found", "error").as_str() {
+            "error" => false,
+            "ignore" => true,
+            v => {
+                return Err(format!(
+                    "invalid delete_not_found option '{}', expect 'error' or 'ignore'",
+                    v
+                ))
+            }
+        };
+        let on_conflict = match opts.get("on_conflict").as_deref() {
+            None => None,
+            Some(v) => {
+                let action = match v {
+                    "error" => OnConflict::Error,
+                    "skip" => OnConflict::Skip,
+                    "update" => OnConflict::Update,
+                    _ => {
+                        return Err(format!(
+                            "invalid on_conflict option '{}', expect 'error', 'skip' or 'update'",
+                            v
+                        ))
+                    }
+                };
+                let key = opts.require("conflict_key")?;
+                if !["reference_id", "email_address", "phone_number"].contains(&key.as_str()) {
+                    return Err(format!(
+                        "invalid conflict_key option '{}', expect 'reference_id', 'email_address' or 'phone_number'",
+                        key
+                    ));
+                }
+                Some((action, key))
+            }
+        };
+        // Dry run can be set on the table or the whole server
+        let dry_run = opts
+            .get("dry_run")
+            .or_else(|| ctx.get_options(OptionsType::Server).get("dry_run"));
+        let dry_run = match dry_run.as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(v) => {
+                return Err(format!(
+                    "invalid dry_run option '{}', expect 'true' or 'false'",
+                    v
+                ))
+            }
+        };
+        this.modify = ModifyState {
+            column_map,
+            // Required by the host for update and delete, not set for insert only tables
+            rowid_column: opts.get("rowid_column").unwrap_or_default(),
+            ignore_not_found,
+            on_conflict,
+            idempotency_ns: opts.require_or("idempotency_namespace", ""),
+            dry_run,
+            ..Default::default()
+        };
+
+        Ok(())
     }
 
-    fn insert(_ctx: &Context, _row: &Row) -> FdwResult {
+    fn insert(_ctx: &Context, row: &Row) -> FdwResult {
+        let this = Self::this_mut();
+
+        // Build CreateCustomer request from the row
+        this.modify.insert_cnt += 1;
+        let body = build_customer(row, &this.modify.column_map, READ_ONLY_FIELDS, false)?;
+
+        // Validate the row locally, nothing is sent until all rows are validated
+        let problems = validate_customer(&body, true);
+        if !problems.is_empty() {
+            let row_no = this.modify.insert_cnt;
+            this.modify
+                .invalid_rows
+                .push(format!("row {}: {}", row_no, problems.join(", ")));
+        }
+        if !this.modify.invalid_rows.is_empty() {
+            return Ok(());
+        }
+
+        // Derive the idempotency key from the row contents, so re-running the same statement
+        // is recognized by Square as duplicate requests. Identical rows in the statement are
+        // told apart by their occurrence.
+        let content = body.to_string();
+        let occurs = this
+            .modify
+            .row_occurs
+            .entry(fnv1a64(content.as_bytes()))
+            .or_default();
+        *occurs += 1;
+        let seed = format!("{}:{}:{}", this.modify.idempotency_ns, occurs, content);
+        let idempotency_key = format!(
+            "{:016x}{:016x}",
+            fnv1a64(seed.as_bytes()),
+            fnv1a64(format!("square:{}", seed).as_bytes())
+        );
+
+        // Buffer the row, customers are created when the statement ends
+        this.modify
+            .creates
+            .push((this.modify.insert_cnt, idempotency_key, body));
+
         Ok(())
     }
 
-    fn update(_ctx: &Context, _rowid: Cell, _row: &Row) -> FdwResult {
+    fn update(_ctx: &Context, rowid: Cell, new_row: &Row) -> FdwResult {
+        let this = Self::this_mut();
+        let id = rowid_to_id(&rowid)?;
+
+        // Build UpdateCustomer request from the updated columns, the customer id is the key
+        // of the request and NULL clears the field on Square side. The version column is passed
+        // through so Square can reject the update if the customer has changed since it was read.
+        let mut skip_fields: Vec<&str> = READ_ONLY_FIELDS
+            .iter()
+            .copied()
+            .filter(|f| *f != "version")
+            .collect();
+        // The rowid column is the key, skip the Square field it is mapped to
+        let rowid_field = this.modify.column_map.field(&this.modify.rowid_column);
+        if let Some(field) = &rowid_field {
+            skip_fields.push(field);
+        }
+        let mut body = build_customer(new_row, &this.modify.column_map, &skip_fields, true)?;
+        if body.get("version").is_some_and(|v| v.is_null()) {
+            body.as_object_mut().map(|v| v.remove("version"));
+        }
+        if body.as_object().is_some_and(|v| v.is_empty()) {
+            return Ok(());
+        }
+
+        // Validate the row locally, nothing is sent until all rows are validated
+        let problems = validate_customer(&body, false);
+        if !problems.is_empty() {
+            this.modify
+                .invalid_rows
+                .push(format!("customer '{}': {}", id, problems.join(", ")));
+        }
+        if !this.modify.invalid_rows.is_empty() {
+            return Ok(());
+        }
+
+        this.modify.updates.push((id, body));
         Ok(())
     }
 
-    fn delete(_ctx: &Context, _rowid: Cell) -> FdwResult {
+    fn delete(_ctx: &Context, rowid: Cell) -> FdwResult {
+        let this = Self::this_mut();
+        let id = rowid_to_id(&rowid)?;
+
+        // Buffer the row and delete customers in batches
+        if !this.modify.deletes.contains(&id) {
+            this.modify.deletes.push(id);
+        }
+        if this.modify.deletes.len() >= BULK_WRITE_SIZE {
+            this.flush_deletes()?;
+        }
+
         Ok(())
     }
 
     fn end_modify(_ctx: &Context) -> FdwResult {
-        Ok(())
+        let this = Self::this_mut();
+
+        // Send the buffered rows only if all rows are valid, otherwise report all invalid rows
+        let result = this
+            .resolve_conflicts()
+            .and_then(|_| this.flush_creates())
+            .and_then(|_| this.flush_updates())
+            .and_then(|_| this.flush_deletes())
+            .and_then(|_| {
+                let invalid_rows = &this.modify.invalid_rows;
+                if invalid_rows.is_empty() {
+                    return Ok(());
+                }
+                Err(format!(
+                    "{} invalid rows, no rows are sent:\n{}",
+                    invalid_rows.len(),
+                    invalid_rows.join("\n")
+                ))
+            });
+
+        this.modify = ModifyState::default();
+        result
     }
 }
 
 bindings::export!(ExampleFdw with_types_in bindings);
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn email_validation() {
+        assert!(is_valid_email("amelia@example.com"));
+        assert!(is_valid_email("a.b+c@mail.example.co.uk"));
+        assert!(!is_valid_email("amelia"));
+        assert!(!is_valid_email("@example.com"));
+        assert!(!is_valid_email("a@@b.c"));
+        assert!(!is_valid_email("a@b@c.com"));
+        assert!(!is_valid_email("amelia@localhost"));
+        assert!(!is_valid_email("amelia@example..com"));
+        assert!(!is_valid_email("amelia@-example.com"));
+        assert!(!is_valid_email("amelia earhart@example.com"));
+    }
+
+    #[test]
+    fn phone_validation() {
+        assert!(is_valid_phone("+14155552671"));
+        assert!(!is_valid_phone("14155552671"));
+        assert!(!is_valid_phone("+04155552671"));
+        assert!(!is_valid_phone("+1 415 555 2671"));
+        assert!(!is_valid_phone("+1234567890123456"));
+    }
 
+    #[test]
+    fn birthday_validation() {
+        assert!(is_valid_birthday("1998-09-21"));
+        assert!(is_valid_birthday("09-21"));
+        assert!(is_valid_birthday("0000-09-21"));
+        assert!(is_valid_birthday("02-29"));
+        assert!(is_valid_birthday("2000-02-29"));
+        assert!(!is_valid_birthday("1900-02-29"));
+        assert!(!is_valid_birthday("2023-02-29"));
+        assert!(!is_valid_birthday("02-31"));
+        assert!(!is_valid_birthday("04-31"));
+        assert!(!is_valid_birthday("13-01"));
+        assert!(!is_valid_birthday("9-21"));
+        assert!(!is_valid_birthday("98-09-21"));
+        assert!(!is_valid_birthday("1998/09/21"));
+    }
 
+    #[test]
+    fn date_parsing() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("1970-01-02"), Some(86_400));
+        assert_eq!(parse_date("1969-12-31"), Some(-86_400));
+        assert_eq!(parse_date("2000-03-01"), Some(951_868_800));
+        assert_eq!(parse_date("0000-09-21"), None);
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-13-01"), None);
+    }
+
+    #[test]
+    fn date_formatting() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(-86_400), "1969-12-31");
+        assert_eq!(format_date(951_868_800), "2000-03-01");
+        for date in ["1600-02-29", "1998-09-21", "2024-02-29", "2100-12-31"] {
+            assert_eq!(parse_date(date).map(format_date).as_deref(), Some(date));
+        }
+    }
+}